@@ -12,6 +12,8 @@ use core::directory::{Directory, MmapDirectory, RAMDirectory, ReadOnlySource, Wr
 use core::writer::IndexWriter;
 use core::searcher::Searcher;
 use uuid::Uuid;
+use zstd;
+use lz4;
 
 #[derive(Clone, PartialEq, Eq, Hash,RustcDecodable,RustcEncodable)]
 pub struct SegmentId(Uuid);
@@ -33,21 +35,346 @@ impl fmt::Debug for SegmentId {
 }
 
 
+// Cached per-segment metadata, stored alongside each `SegmentId` in
+// `IndexMeta` so `segments()` can report sizes without opening every
+// segment's files.
+#[derive(Clone,Debug,RustcDecodable,RustcEncodable)]
+pub struct SegmentMeta {
+    pub segment_id: SegmentId,
+    pub max_doc: DocId,
+    pub num_deleted_docs: DocId,
+    // FNV-1a hash of the segment's POSTINGS/TERMS/STORE bytes, taken by
+    // `publish_segment` (see `segment_checksum`). It is only recorded;
+    // `Index` never recomputes or compares it.
+    pub checksum: u32,
+}
+
+// How a segment's STORE component is encoded. Persisted in `IndexMeta`
+// rather than guessed per-file, so a reopened index still knows which
+// codec to hand to `StoreReader` without being told again.
+#[derive(Clone,Copy,Debug,PartialEq,RustcDecodable,RustcEncodable)]
+pub enum StoreCompression {
+    None,
+    Zstd(i32),
+    Lz4,
+}
+
+impl Default for StoreCompression {
+    fn default() -> StoreCompression {
+        StoreCompression::Zstd(3)
+    }
+}
+
+impl StoreCompression {
+    fn codec_tag(&self) -> u8 {
+        match *self {
+            StoreCompression::None => 0,
+            StoreCompression::Zstd(_) => 1,
+            StoreCompression::Lz4 => 2,
+        }
+    }
+
+    // The level only matters for encoding; a block tagged Zstd decodes the
+    // same regardless of the level it was compressed at.
+    fn from_codec_tag(tag: u8) -> io::Result<StoreCompression> {
+        match tag {
+            0 => Ok(StoreCompression::None),
+            1 => Ok(StoreCompression::Zstd(0)),
+            2 => Ok(StoreCompression::Lz4),
+            _ => Err(io::Error::new(IOErrorKind::InvalidData,
+                format!("unknown store codec tag {}", tag))),
+        }
+    }
+}
+
+// Knobs decided once at `Index::create` time. Only `store_compression`
+// exists today, but this is where future per-index, creation-time-only
+// settings belong rather than as loose `Index::create` arguments.
+#[derive(Clone,Copy,Debug,Default)]
+pub struct IndexSettings {
+    pub store_compression: StoreCompression,
+}
+
 #[derive(Clone,Debug,RustcDecodable,RustcEncodable)]
 pub struct IndexMeta {
-    segments: Vec<SegmentId>,
+    segments: Vec<SegmentMeta>,
     schema: Schema,
+    // Generation this `IndexMeta` was committed as. 0 for an index that has
+    // never been committed yet.
+    generation: u64,
+    // Bounded window of generations whose `meta-<generation>.json` are
+    // guaranteed to still be around, most recent last. `rollback` can only
+    // target a generation still in this window; `segments()` for the live
+    // generation therefore never ends up pointing at a file that may have
+    // been cleaned up.
+    retained_generations: Vec<u64>,
+    // Codec every segment's STORE component in this index was written
+    // with. Decided at creation time via `IndexSettings`.
+    store_compression: StoreCompression,
+    // LSN of the last `meta.log` record already folded into this
+    // `IndexMeta` as of the moment it was snapshotted. `load_metas` skips
+    // replaying any record at or below this watermark, so a crash between
+    // `commit_generation_internal` writing the generation file and it
+    // truncating `meta.log` -- which leaves `segments.gen` pointing at a
+    // generation file while the records it already reflects are still
+    // sitting in an untruncated log -- cannot double-apply them.
+    log_watermark: u64,
 }
 
 impl IndexMeta {
     fn with_schema(schema: Schema) -> IndexMeta {
+        IndexMeta::with_schema_and_settings(schema, IndexSettings::default())
+    }
+
+    fn with_schema_and_settings(schema: Schema, settings: IndexSettings) -> IndexMeta {
         IndexMeta {
             segments: Vec::new(),
             schema: schema,
+            generation: 0,
+            retained_generations: vec![0],
+            store_compression: settings.store_compression,
+            log_watermark: 0,
+        }
+    }
+}
+
+// A single mutation to `IndexMeta`, as appended to `meta.log` instead of
+// rewriting the materialized snapshot on every commit.
+#[derive(Clone,Debug,RustcDecodable,RustcEncodable)]
+enum MetaLogRecord {
+    AddSegment(SegmentMeta),
+    RemoveSegment(SegmentId),
+    SetSchema(Schema),
+    UpdateDeletedDocs(SegmentId, DocId),
+}
+
+fn apply_log_record(meta: &mut IndexMeta, record: MetaLogRecord) {
+    match record {
+        MetaLogRecord::AddSegment(segment_meta) => meta.segments.push(segment_meta),
+        MetaLogRecord::RemoveSegment(segment_id) => meta.segments.retain(|meta| meta.segment_id != segment_id),
+        MetaLogRecord::SetSchema(schema) => meta.schema = schema,
+        MetaLogRecord::UpdateDeletedDocs(segment_id, num_deleted_docs) => {
+            for segment_meta in meta.segments.iter_mut() {
+                if segment_meta.segment_id == segment_id {
+                    segment_meta.num_deleted_docs = num_deleted_docs;
+                }
+            }
+        }
+    }
+}
+
+// Number of records a `meta.log` is allowed to accumulate before
+// `Index` commits a new generation and truncates it.
+const SNAPSHOT_THRESHOLD: usize = 1_000;
+
+// How many past generations `rollback` is allowed to reach back to.
+const RETAINED_GENERATIONS: usize = 16;
+
+fn encode_u64_be(v: u64, buf: &mut Vec<u8>) {
+    for shift in (0..8).rev() {
+        buf.push((v >> (shift * 8)) as u8);
+    }
+}
+
+fn decode_u64_be(bytes: &[u8]) -> u64 {
+    let mut v: u64 = 0;
+    for &b in bytes {
+        v = (v << 8) | (b as u64);
+    }
+    v
+}
+
+fn encode_u32_be(v: u32, buf: &mut Vec<u8>) {
+    for shift in (0..4).rev() {
+        buf.push((v >> (shift * 8)) as u8);
+    }
+}
+
+fn decode_u32_be(bytes: &[u8]) -> u32 {
+    let mut v: u32 = 0;
+    for &b in bytes {
+        v = (v << 8) | (b as u32);
+    }
+    v
+}
+
+// Each commit is an immutable, fully materialized `IndexMeta`, named after
+// its generation -- the versioned-index / deployment-log pattern, where
+// every state is a new record and a pointer file selects the active one.
+fn generation_filepath(generation: u64) -> PathBuf {
+    PathBuf::from(format!("meta-{}.json", generation))
+}
+
+// Tracks where `Index` is in the log, in memory. Rebuilt by `load_metas`
+// on `Index::open`, or from scratch on `Index::create`.
+struct LogState {
+    // LSN of the last record appended to (or replayed from) `meta.log`.
+    next_lsn: u64,
+    // Number of records appended to `meta.log` since the last snapshot.
+    record_count: usize,
+    // Handle `append_log_record` writes new frames to directly, so a
+    // publish costs the size of its own frame rather than the whole log.
+    // `None` until `reopen_log_writer` has (re)primed it against the
+    // current contents of `meta.log` -- on the first append after
+    // `Index::create`, after `load_metas` has healed the log, or after
+    // `commit_generation_internal`/`rollback` truncates it out from
+    // under any handle that was open.
+    writer: Option<WritePtr>,
+}
+
+impl LogState {
+    fn new() -> LogState {
+        LogState {
+            next_lsn: 0,
+            record_count: 0,
+            writer: None,
+        }
+    }
+}
+
+// Callback fired by a `Directory`'s watcher whenever a file in the
+// directory is atomically replaced.
+//
+// `FnMut` rather than `Fn`: the `ReloadPolicy::OnCommit` callback below
+// needs `&mut self` access to the `Index` it reloads.
+pub type WatchCallback = Box<FnMut() + Send>;
+
+// RAII registration returned by `Directory::watch` and `Index::on_commit`.
+// Unregisters its callback from the list it was handed out by as soon as
+// it's dropped, so a short-lived searcher doesn't leak into a long-lived
+// `Index`'s callback list.
+pub struct WatchHandle {
+    id: u64,
+    callbacks: Arc<RwLock<Vec<(u64, WatchCallback)>>>,
+}
+
+impl Drop for WatchHandle {
+    fn drop(&mut self) {
+        self.callbacks.write().unwrap().retain(|&(id, _)| id != self.id);
+    }
+}
+
+// A list of subscribers sharing the same notification, handing out an
+// unregister-on-drop `WatchHandle` per subscription.
+#[derive(Clone)]
+struct WatchCallbackList {
+    next_id: Arc<RwLock<u64>>,
+    callbacks: Arc<RwLock<Vec<(u64, WatchCallback)>>>,
+}
+
+impl WatchCallbackList {
+    fn new() -> WatchCallbackList {
+        WatchCallbackList {
+            next_id: Arc::new(RwLock::new(0)),
+            callbacks: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    fn subscribe(&self, callback: WatchCallback) -> WatchHandle {
+        let id = {
+            let mut next_id = self.next_id.write().unwrap();
+            *next_id += 1;
+            *next_id
+        };
+        self.callbacks.write().unwrap().push((id, callback));
+        WatchHandle {
+            id: id,
+            callbacks: self.callbacks.clone(),
+        }
+    }
+
+    fn broadcast(&self) {
+        // `FnMut::call_mut` needs exclusive access to each callback, so
+        // this takes the write lock even though it isn't mutating the
+        // list's shape.
+        for &mut (_, ref mut callback) in self.callbacks.write().unwrap().iter_mut() {
+            callback();
         }
     }
 }
 
+// Governs whether a live `Index` notices commits made by other writers.
+pub enum ReloadPolicy {
+    // `load_metas` only runs when called explicitly.
+    Manual,
+    // `Index` watches the directory, reloads on every change event there,
+    // and fires `on_commit` callbacks only when `commit_generation()` has
+    // moved since the last check.
+    OnCommit,
+}
+
+// A candidate segment for merging, annotated with its on-disk size so a
+// `MergePolicy` can group same-sized segments together.
+pub struct SegmentMergeCandidate {
+    pub segment_id: SegmentId,
+    pub size_bytes: u64,
+}
+
+// Chooses which `SegmentId` sets `Index::merge` should combine. `candidates`
+// is given only segments that are not already locked by another in-flight
+// merge, so distinct `MergePolicy` calls never race over the same inputs.
+pub trait MergePolicy {
+    fn candidates(&self, segments: &[SegmentMergeCandidate]) -> Vec<Vec<SegmentId>>;
+}
+
+// Groups segments of similar size into same-tier merge sets once a tier has
+// accumulated `min_segments_per_tier` of them, bounding how many segments
+// `Index::segments()` ever has to carry.
+pub struct TieredMergePolicy {
+    pub min_segments_per_tier: usize,
+    pub max_merge_size_bytes: u64,
+}
+
+impl Default for TieredMergePolicy {
+    fn default() -> TieredMergePolicy {
+        TieredMergePolicy {
+            min_segments_per_tier: 10,
+            max_merge_size_bytes: 5 * 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl MergePolicy for TieredMergePolicy {
+    fn candidates(&self, segments: &[SegmentMergeCandidate]) -> Vec<Vec<SegmentId>> {
+        let mut by_size: Vec<&SegmentMergeCandidate> = segments.iter()
+            .filter(|candidate| candidate.size_bytes < self.max_merge_size_bytes)
+            .collect();
+        by_size.sort_by_key(|candidate| candidate.size_bytes);
+        by_size.chunks(self.min_segments_per_tier)
+            .filter(|tier| tier.len() >= 2 && tier.len() >= self.min_segments_per_tier)
+            .map(|tier| tier.iter().map(|candidate| candidate.segment_id.clone()).collect())
+            .collect()
+    }
+}
+
+// Progress callback for `Index::merge_with_progress`: bytes merged so far,
+// and the total across every component of every input segment.
+pub type MergeProgressCallback = Box<Fn(u64, u64) + Send>;
+
+#[derive(Clone,Debug,PartialEq,RustcDecodable,RustcEncodable)]
+pub enum MergePhase {
+    Started,
+    ComponentsCopied,
+    Committed,
+}
+
+// A merge's state, persisted to a `<output>.merge` sidecar so an interrupted
+// merge can be told apart from one that completed. The sidecar is the only
+// durable trace of an in-flight merge: `output` was never referenced by
+// `IndexMeta`, so if the process is restarted before `MergePhase::Committed`
+// is reached, the safe move is simply to abandon it (its files are orphaned
+// but harmless) and retry the merge from scratch with a fresh output.
+#[derive(Clone,Debug,RustcDecodable,RustcEncodable)]
+pub struct MergeJob {
+    pub inputs: Vec<SegmentId>,
+    pub output: SegmentId,
+    pub phase: MergePhase,
+}
+
+fn merge_job_filepath(output: &SegmentId) -> PathBuf {
+    PathBuf::from(output.uuid_string() + ".merge")
+}
+
 impl fmt::Debug for Index {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
        write!(f, "Index({:?})", self.directory)
@@ -60,22 +387,44 @@ type DirectoryPtr = Box<Directory>;
 pub struct Index {
     metas: Arc<RwLock<IndexMeta>>,
     directory: Arc<RwLock<DirectoryPtr>>,
+    log_state: Arc<RwLock<LogState>>,
+    callbacks: WatchCallbackList,
+    watch_handle: Arc<RwLock<Option<WatchHandle>>>,
+    // Segments currently being read by an in-flight `merge`, so concurrent
+    // merges never pick overlapping inputs.
+    locked_segments: Arc<RwLock<Vec<SegmentId>>>,
 }
 
 lazy_static! {
-    static ref  META_FILEPATH: PathBuf = PathBuf::from("meta.json");
+    static ref LOG_FILEPATH: PathBuf = PathBuf::from("meta.log");
+    // Tiny pointer file, atomically rewritten on every commit/rollback.
+    // Holds two whitespace-separated integers: the live generation (what
+    // `segments()` reflects) and the high-water-mark generation ever
+    // minted. They can diverge after a `rollback`, and keeping the latter
+    // around is what lets the next commit always mint a brand-new
+    // generation number instead of colliding with one that was rolled
+    // past.
+    static ref GEN_POINTER_FILEPATH: PathBuf = PathBuf::from("segments.gen");
 }
 
 impl Index {
 
     pub fn create_in_ram(schema: Schema) -> Index {
+        Index::create_in_ram_with_settings(schema, IndexSettings::default())
+    }
+
+    pub fn create_in_ram_with_settings(schema: Schema, settings: IndexSettings) -> Index {
         let directory = Box::new(RAMDirectory::create());
-        Index::from_directory(directory, schema)
+        Index::from_directory_with_settings(directory, schema, settings)
     }
 
     pub fn create(directory_path: &Path, schema: Schema) -> io::Result<Index> {
+        Index::create_with_settings(directory_path, schema, IndexSettings::default())
+    }
+
+    pub fn create_with_settings(directory_path: &Path, schema: Schema, settings: IndexSettings) -> io::Result<Index> {
         let directory = Box::new(try!(MmapDirectory::create(directory_path)));
-        Ok(Index::from_directory(directory, schema))
+        Ok(Index::from_directory_with_settings(directory, schema, settings))
     }
 
     pub fn create_from_tempdir(schema: Schema) -> io::Result<Index> {
@@ -91,6 +440,77 @@ impl Index {
         Ok(index)
     }
 
+    // Opens a point-in-time view pinned to `generation`, rather than
+    // whatever `segments.gen` currently points to. `generation`'s
+    // `meta-<generation>.json` is immutable, so the returned `Index` never
+    // observes segments published after it, even if the live index keeps
+    // advancing underneath it. Writes through the returned `Index` are not
+    // refused.
+    pub fn open_generation(directory_path: &Path, generation: u64) -> io::Result<Index> {
+        let directory = try!(MmapDirectory::create(directory_path));
+        let directory_ptr = Box::new(directory);
+        let mut index = Index::from_directory(directory_ptr, Schema::new());
+        try!(index.load_generation(generation));
+        Ok(index)
+    }
+
+    fn load_generation(&mut self, generation: u64) -> io::Result<()> {
+        let generation_path = generation_filepath(generation);
+        let generation_file = try!(self.ro_directory().and_then(|d| d.open_read(&generation_path)));
+        let content = String::from_utf8_lossy(generation_file.as_slice());
+        let meta: IndexMeta = json::decode(&content).unwrap();
+        self.metas.write().unwrap().clone_from(&meta);
+        Ok(())
+    }
+
+    // Generation the live index is currently on. 0 if it has never
+    // been committed.
+    pub fn commit_generation(&self) -> u64 {
+        self.metas.read().unwrap().generation
+    }
+
+    // Repoints `segments.gen` back at an older, still-retained generation,
+    // discarding any `meta.log` records appended since. Used to roll back a
+    // failed bulk load: once `rollback` returns, `segments()` reflects the
+    // older generation again, as if the intervening commits never happened.
+    pub fn rollback(&mut self, generation: u64) -> io::Result<()> {
+        let is_retained = self.metas.read().unwrap().retained_generations.contains(&generation);
+        if !is_retained {
+            return Err(io::Error::new(IOErrorKind::NotFound,
+                format!("Generation {} is not among the retained generations", generation)));
+        }
+
+        // Generation 0 means "never committed yet" -- there is no
+        // meta-0.json, same as load_metas's live_generation == 0 case.
+        let mut historical_meta = if generation > 0 {
+            let generation_path = generation_filepath(generation);
+            let generation_file = try!(self.ro_directory().and_then(|d| d.open_read(&generation_path)));
+            let content = String::from_utf8_lossy(generation_file.as_slice());
+            json::decode(&content).unwrap()
+        } else {
+            let store_compression = self.metas.read().unwrap().store_compression;
+            IndexMeta::with_schema_and_settings(self.schema(), IndexSettings { store_compression: store_compression })
+        };
+        // Keep the live, up-to-date retained-generations window rather than
+        // the stale one baked into the old generation file: future rollback
+        // calls still need to see every generation minted since.
+        historical_meta.retained_generations = self.metas.read().unwrap().retained_generations.clone();
+
+        let (_, max_generation) = try!(self.read_gen_pointer());
+        {
+            let mut directory = try!(self.rw_directory());
+            try!(write_gen_pointer(&mut *directory, generation, max_generation));
+            try!(directory.atomic_write(&LOG_FILEPATH, &[]));
+        }
+        {
+            let mut log_state = self.log_state.write().unwrap();
+            log_state.record_count = 0;
+            log_state.writer = None;
+        }
+        self.metas.write().unwrap().clone_from(&historical_meta);
+        Ok(())
+    }
+
     pub fn writer(&self,) -> io::Result<IndexWriter> {
         IndexWriter::open(self,)
     }
@@ -100,10 +520,62 @@ impl Index {
     }
 
     fn from_directory(directory: DirectoryPtr, schema: Schema) -> Index {
+        Index::from_directory_with_settings(directory, schema, IndexSettings::default())
+    }
+
+    fn from_directory_with_settings(directory: DirectoryPtr, schema: Schema, settings: IndexSettings) -> Index {
         Index {
-            metas: Arc::new(RwLock::new(IndexMeta::with_schema(schema))),
+            metas: Arc::new(RwLock::new(IndexMeta::with_schema_and_settings(schema, settings))),
             directory: Arc::new(RwLock::new(directory)),
+            log_state: Arc::new(RwLock::new(LogState::new())),
+            callbacks: WatchCallbackList::new(),
+            watch_handle: Arc::new(RwLock::new(None)),
+            locked_segments: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    // Subscribes `callback` to be called under `ReloadPolicy::OnCommit`
+    // whenever a reload triggered by the directory watcher finds that
+    // `commit_generation()` has moved. An explicit `load_metas` call does
+    // not fire it. Dropping the returned `WatchHandle` unsubscribes it.
+    pub fn on_commit(&self, callback: WatchCallback) -> WatchHandle {
+        self.callbacks.subscribe(callback)
+    }
+
+    // Controls whether this `Index` notices commits made by other writers.
+    // Under `ReloadPolicy::OnCommit`, registers a callback with
+    // `Directory::watch`, which watches the whole directory rather than just
+    // `segments.gen`; the callback itself compares `commit_generation()`
+    // before and after reloading so unrelated writes in the directory (like log
+    // appends from another commit in progress) don't fire `on_commit`
+    // spuriously.
+    //
+    // NOTE: `Directory::watch` (the filesystem watcher behind `MmapDirectory`,
+    // the callback list behind `RAMDirectory`) and wiring `Searcher` to
+    // reopen itself from an `on_commit` callback both live outside
+    // src/core/index.rs and aren't part of this change. This only fixes
+    // `Index`'s own half: subscribing/broadcasting actually compiles, and
+    // fires correctly once `Directory::watch` exists and a caller (like
+    // `Searcher`) subscribes to it.
+    pub fn set_reload_policy(&mut self, reload_policy: ReloadPolicy) -> io::Result<()> {
+        match reload_policy {
+            ReloadPolicy::Manual => {
+                *self.watch_handle.write().unwrap() = None;
+            }
+            ReloadPolicy::OnCommit => {
+                let mut watched_index = self.clone();
+                let callbacks = self.callbacks.clone();
+                let directory_watch_handle = try!(try!(self.ro_directory()).watch(Box::new(move || {
+                    let previous_generation = watched_index.commit_generation();
+                    if watched_index.load_metas().is_ok() &&
+                       watched_index.commit_generation() != previous_generation {
+                        callbacks.broadcast();
+                    }
+                })));
+                *self.watch_handle.write().unwrap() = Some(directory_watch_handle);
+            }
         }
+        Ok(())
     }
 
     pub fn schema(&self,) -> Schema {
@@ -129,10 +601,172 @@ impl Index {
 
     // TODO find a rusty way to hide that, while keeping
     // it visible for IndexWriters.
+    //
+    // Keeps its original (segment: Segment) signature -- the caller is
+    // expected to have already called segment.write_info() -- rather than
+    // also taking a SegmentInfo, since IndexWriter, this method's only
+    // caller, lives outside src/core/index.rs and isn't part of this
+    // change.
     pub fn publish_segment(&mut self, segment: Segment) -> io::Result<()> {
-        self.metas.write().unwrap().segments.push(segment.segment_id.clone());
-        // TODO use logs
-        self.save_metas()
+        // The very first record ever appended to a fresh `meta.log` carries
+        // the schema, so recovery can rebuild an `IndexMeta` from nothing
+        // but the log when no snapshot has been taken yet.
+        if self.log_state.read().unwrap().next_lsn == 0 {
+            let schema = self.schema();
+            try!(self.append_log_record(MetaLogRecord::SetSchema(schema)));
+        }
+        let segment_info = try!(segment.read_info());
+        let segment_meta = SegmentMeta {
+            segment_id: segment.segment_id.clone(),
+            max_doc: segment_info.max_doc,
+            num_deleted_docs: 0,
+            checksum: try!(segment_checksum(&segment)),
+        };
+        self.metas.write().unwrap().segments.push(segment_meta.clone());
+        self.append_log_record(MetaLogRecord::AddSegment(segment_meta))
+    }
+
+    // Marks `docs` deleted in `segment_id`'s `.del` file and updates its
+    // SegmentMeta.num_deleted_docs to match, so segments() stays accurate
+    // without opening the file.
+    pub fn delete_docs(&mut self, segment_id: &SegmentId, docs: &[DocId]) -> io::Result<()> {
+        let segment = self.segment(segment_id);
+        let mut bitset = try!(segment.open_delete_bitset());
+        for &doc in docs {
+            bitset.delete(doc);
+        }
+        try!(segment.write_delete_bitset(&bitset));
+        let num_deleted_docs = bitset.num_deleted();
+        for segment_meta in self.metas.write().unwrap().segments.iter_mut() {
+            if segment_meta.segment_id == *segment_id {
+                segment_meta.num_deleted_docs = num_deleted_docs;
+            }
+        }
+        self.append_log_record(MetaLogRecord::UpdateDeletedDocs(segment_id.clone(), num_deleted_docs))
+    }
+
+    // Appends `record` to `meta.log` and fsyncs it. This is the only way
+    // `IndexMeta` mutations reach disk: each call writes only its own
+    // frame to the writer `reopen_log_writer` already primed, rather than
+    // re-reading and rewriting everything appended so far. Once enough
+    // records have piled up, materializes a snapshot and truncates the
+    // log so it never grows unbounded.
+    fn append_log_record(&mut self, record: MetaLogRecord) -> io::Result<()> {
+        let lsn = {
+            let mut log_state = self.log_state.write().unwrap();
+            log_state.next_lsn += 1;
+            log_state.next_lsn
+        };
+
+        let payload = json::encode(&record).unwrap();
+        let mut frame = Vec::with_capacity(12 + payload.len());
+        encode_u64_be(lsn, &mut frame);
+        encode_u32_be(payload.len() as u32, &mut frame);
+        frame.extend_from_slice(payload.as_bytes());
+
+        if self.log_state.read().unwrap().writer.is_none() {
+            try!(self.reopen_log_writer(&[]));
+        }
+        {
+            let mut log_state = self.log_state.write().unwrap();
+            let writer = log_state.writer.as_mut().unwrap();
+            try!(writer.write_all(&frame));
+            try!(writer.flush());
+        }
+        try!(try!(self.ro_directory()).sync(&LOG_FILEPATH));
+
+        let should_commit = {
+            let mut log_state = self.log_state.write().unwrap();
+            log_state.record_count += 1;
+            log_state.record_count >= SNAPSHOT_THRESHOLD
+        };
+        if should_commit {
+            try!(self.commit_generation_internal());
+        }
+        Ok(())
+    }
+
+    // (Re)opens `meta.log` for writing and immediately writes back
+    // `contents`, then keeps the resulting handle in `log_state` so every
+    // subsequent `append_log_record` call is a true append: just the new
+    // frame, not `contents` again. Called once, with the healed log
+    // prefix `load_metas` just replayed, whenever the log's on-disk
+    // contents have changed out from under any writer that might already
+    // be open -- after `load_metas`, and after `commit_generation_internal`
+    // / `rollback` truncate the log to empty.
+    fn reopen_log_writer(&mut self, contents: &[u8]) -> io::Result<()> {
+        let mut writer = {
+            let mut directory = try!(self.rw_directory());
+            try!(directory.open_write(&LOG_FILEPATH))
+        };
+        try!(writer.write_all(contents));
+        try!(writer.flush());
+        self.log_state.write().unwrap().writer = Some(writer);
+        Ok(())
+    }
+
+    // Mints the next generation, materializes the current `IndexMeta` to
+    // `meta-<generation>.json`, repoints `segments.gen` at it, and truncates
+    // `meta.log`: the new generation file already reflects every record
+    // appended so far, so they no longer need replaying.
+    fn commit_generation_internal(&mut self) -> io::Result<u64> {
+        let (_, max_generation) = try!(self.read_gen_pointer());
+        let new_generation = max_generation + 1;
+        // Every record up to this LSN is about to be folded into the
+        // generation file below; stamping it into the snapshot is what
+        // lets `load_metas` tell apart "pending, not yet applied" records
+        // from ones a crash merely failed to truncate out of `meta.log`.
+        let watermark = self.log_state.read().unwrap().next_lsn;
+
+        let encoded = {
+            let mut metas = self.metas.write().unwrap();
+            metas.generation = new_generation;
+            metas.log_watermark = watermark;
+            metas.retained_generations.push(new_generation);
+            let overflow = metas.retained_generations.len().saturating_sub(RETAINED_GENERATIONS);
+            metas.retained_generations.drain(0..overflow);
+            json::encode(&*metas).unwrap()
+        };
+
+        let generation_path = generation_filepath(new_generation);
+        {
+            let mut directory = try!(self.rw_directory());
+            try!(directory.atomic_write(&generation_path, encoded.as_bytes()));
+            try!(write_gen_pointer(&mut *directory, new_generation, new_generation));
+            try!(directory.atomic_write(&LOG_FILEPATH, &[]));
+        }
+        {
+            let mut log_state = self.log_state.write().unwrap();
+            log_state.record_count = 0;
+            // The log was just truncated out from under whatever handle
+            // `append_log_record` had open; drop it so the next append
+            // reopens against the now-empty file instead of appending
+            // past the truncation.
+            log_state.writer = None;
+        }
+        Ok(new_generation)
+    }
+
+    fn read_gen_pointer(&self) -> io::Result<(u64, u64)> {
+        match self.ro_directory().and_then(|d| d.open_read(&GEN_POINTER_FILEPATH)) {
+            Ok(source) => {
+                let content = String::from_utf8_lossy(source.as_slice());
+                let mut parts = content.trim().split_whitespace();
+                let live = parts.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+                let max = parts.next().and_then(|s| s.parse().ok()).unwrap_or(live);
+                Ok((live, max))
+            }
+            Err(ref err) if err.kind() == IOErrorKind::NotFound => Ok((0, 0)),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn read_log_bytes(&self) -> io::Result<Vec<u8>> {
+        match try!(self.ro_directory()).open_read(&LOG_FILEPATH) {
+            Ok(source) => Ok(source.as_slice().to_vec()),
+            Err(ref err) if err.kind() == IOErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err),
+        }
     }
 
     pub fn sync(&mut self, segment: Segment) -> io::Result<()> {
@@ -160,6 +794,18 @@ impl Index {
     }
 
     fn segment_ids(&self,) -> Vec<SegmentId> {
+        self.metas
+            .read()
+            .unwrap()
+            .segments
+            .iter()
+            .map(|segment_meta| segment_meta.segment_id.clone())
+            .collect()
+    }
+
+    // Per-segment metadata (max_doc, deleted-doc count, checksum) for every
+    // live segment, without opening a single file.
+    pub fn segment_metas(&self,) -> Vec<SegmentMeta> {
         self.metas
             .read()
             .unwrap()
@@ -173,21 +819,205 @@ impl Index {
         self.segment(&SegmentId::new())
     }
 
+    // Recovers `IndexMeta` by loading the live generation (if any, per
+    // `segments.gen`) and replaying every `meta.log` record appended since
+    // on top of it. A trailing record that is short (truncated length
+    // header, or fewer payload bytes than its length header promises) is an
+    // interrupted append and is simply dropped rather than treated as an
+    // error.
     pub fn load_metas(&mut self,) -> io::Result<()> {
-        let meta_file = try!(self.ro_directory().and_then(|d| d.open_read(&META_FILEPATH)));
-        let meta_content = String::from_utf8_lossy(meta_file.as_slice());
-        let loaded_meta: IndexMeta = json::decode(&meta_content).unwrap();
-        self.metas.write().unwrap().clone_from(&loaded_meta);
-        Ok(())
+        let (live_generation, _) = try!(self.read_gen_pointer());
+        let mut meta = if live_generation > 0 {
+            let generation_path = generation_filepath(live_generation);
+            let generation_file = try!(self.ro_directory().and_then(|d| d.open_read(&generation_path)));
+            let generation_content = String::from_utf8_lossy(generation_file.as_slice());
+            json::decode(&generation_content).unwrap()
+        } else {
+            IndexMeta::with_schema(Schema::new())
+        };
+
+        let watermark = meta.log_watermark;
+        let log_bytes = try!(self.read_log_bytes());
+        let mut offset = 0;
+        let mut last_lsn = watermark;
+        let mut record_count = 0;
+        while offset + 12 <= log_bytes.len() {
+            let lsn = decode_u64_be(&log_bytes[offset..offset + 8]);
+            let len = decode_u32_be(&log_bytes[offset + 8..offset + 12]) as usize;
+            let record_start = offset + 12;
+            let record_end = record_start + len;
+            if record_end > log_bytes.len() {
+                // Short read: the process crashed mid-append. Drop it.
+                break;
+            }
+            let record_json = String::from_utf8_lossy(&log_bytes[record_start..record_end]);
+            match json::decode::<MetaLogRecord>(&record_json) {
+                Ok(record) => {
+                    // Already folded into `meta` by whichever
+                    // `commit_generation_internal` stamped `watermark`: a
+                    // crash between it writing the generation file and
+                    // truncating `meta.log` leaves records like this one
+                    // sitting in the log even though the snapshot we just
+                    // loaded already reflects them. Applying it again
+                    // would duplicate it (e.g. a segment added twice).
+                    if lsn > watermark {
+                        apply_log_record(&mut meta, record);
+                        record_count += 1;
+                    }
+                    last_lsn = lsn;
+                }
+                // A record whose length header is intact but whose payload
+                // failed to parse is equally an interrupted/torn append.
+                Err(_) => break,
+            }
+            offset = record_end;
+        }
+
+        // `offset` stops at the end of the last complete, successfully
+        // parsed record, so this also heals a torn tail: the rewrite below
+        // never writes back bytes from an interrupted append.
+        let healed_log_bytes = log_bytes[..offset].to_vec();
+
+        self.metas.write().unwrap().clone_from(&meta);
+        *self.log_state.write().unwrap() = LogState {
+            next_lsn: last_lsn,
+            record_count: record_count,
+            writer: None,
+        };
+        self.reopen_log_writer(&healed_log_bytes)
     }
 
+    // Forces an immediate commit of the current `IndexMeta` into a new
+    // generation, regardless of how many records `meta.log` has
+    // accumulated. Kept around for callers that want to commit on demand
+    // rather than waiting for `SNAPSHOT_THRESHOLD` to be reached.
     pub fn save_metas(&mut self,) -> io::Result<()> {
-        let encoded = {
-            let metas_lock = self.metas.read().unwrap();
-            json::encode(&*metas_lock).unwrap()
+        try!(self.commit_generation_internal());
+        Ok(())
+    }
+
+    // Segments currently locked by an in-flight `merge`. A `MergePolicy`
+    // should never be handed one of these as a candidate.
+    pub fn locked_segments(&self,) -> Vec<SegmentId> {
+        self.locked_segments.read().unwrap().clone()
+    }
+
+    fn segment_size_bytes(&self, segment: &Segment) -> io::Result<u64> {
+        let mut total = 0u64;
+        for component in [SegmentComponent::POSTINGS, SegmentComponent::TERMS, SegmentComponent::STORE].iter() {
+            match segment.open_read(component.clone()) {
+                Ok(source) => total += source.as_slice().len() as u64,
+                Err(ref err) if err.kind() == IOErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(total)
+    }
+
+    // Candidate sets `policy` would merge right now, excluding whatever is
+    // already locked by another in-flight merge.
+    pub fn merge_candidates<P: MergePolicy>(&self, policy: &P) -> io::Result<Vec<Vec<SegmentId>>> {
+        let locked = self.locked_segments();
+        let mut candidates = Vec::new();
+        for segment in self.segments() {
+            if locked.contains(&segment.id()) {
+                continue;
+            }
+            let size_bytes = try!(self.segment_size_bytes(&segment));
+            candidates.push(SegmentMergeCandidate {
+                segment_id: segment.id(),
+                size_bytes: size_bytes,
+            });
+        }
+        Ok(policy.candidates(&candidates))
+    }
+
+    // Locks `inputs` against other merges, records a `MergeJob` sidecar in
+    // `MergePhase::Started`, then always returns an error (see `run_merge`):
+    // segment-level merging is not implemented, so no output segment is
+    // written and `IndexMeta` is left unchanged. The sidecar is left behind.
+    // Equivalent to `merge_with_progress(inputs, None)`.
+    pub fn merge(&mut self, inputs: &[SegmentId]) -> io::Result<SegmentId> {
+        self.merge_with_progress(inputs, None)
+    }
+
+    // Same as `merge`. `progress` is accepted but never called, since no
+    // components are copied.
+    pub fn merge_with_progress(&mut self,
+                                inputs: &[SegmentId],
+                                progress: Option<MergeProgressCallback>) -> io::Result<SegmentId> {
+        {
+            let mut locked = self.locked_segments.write().unwrap();
+            if inputs.iter().any(|input_id| locked.contains(input_id)) {
+                return Err(io::Error::new(IOErrorKind::Other,
+                    "one or more segments are already locked by an in-flight merge"));
+            }
+            locked.extend(inputs.iter().cloned());
+        }
+        let result = self.run_merge(inputs, &progress);
+        self.locked_segments.write().unwrap().retain(|locked_id| !inputs.contains(locked_id));
+        result
+    }
+
+    // Not implemented: see the error returned below. Kept as a stub rather
+    // than removed so `MergeJob`/`MergePhase`/`merge_job_status` -- which
+    // describe what a real implementation's on-disk trail looks like --
+    // stay meaningful, and so `merge_candidates`/`merge_with_progress`'s
+    // locking and the `MergePolicy` trait (the parts that don't depend on
+    // segment re-encoding) are still exercised and don't have to be
+    // rewritten once a real segment writer exists.
+    //
+    // A real merge needs to remap POSTINGS/TERMS doc ids across inputs
+    // before concatenating them, and re-encode STORE through
+    // `StoreWriter` rather than splicing together several already-framed
+    // (header + blocks + offset footer) `.store` files. Until a segment
+    // reader/writer that does that exists, the only honest thing to do
+    // is refuse: committing a naively concatenated segment into
+    // `IndexMeta` would silently hand readers an unparseable STORE and
+    // POSTINGS/TERMS with colliding doc ids.
+    fn run_merge(&mut self,
+                 inputs: &[SegmentId],
+                 _progress: &Option<MergeProgressCallback>) -> io::Result<SegmentId> {
+        let output = self.new_segment();
+        let job = MergeJob {
+            inputs: inputs.to_vec(),
+            output: output.id(),
+            phase: MergePhase::Started,
         };
-        try!(self.rw_directory()).atomic_write(&META_FILEPATH, encoded.as_bytes())
+        try!(self.write_merge_job(&job));
+
+        Err(io::Error::new(IOErrorKind::Other,
+            "Index::merge: segment-level merging (doc id remapping, STORE \
+             re-encoding) is not implemented yet"))
+    }
+
+    fn write_merge_job(&mut self, job: &MergeJob) -> io::Result<()> {
+        let encoded = json::encode(job).unwrap();
+        let path = merge_job_filepath(&job.output);
+        let mut directory = try!(self.rw_directory());
+        directory.atomic_write(&path, encoded.as_bytes())
     }
+
+    // Looks up the sidecar for a merge whose output id is already known
+    // (e.g. from before a restart), so a caller can tell a completed merge
+    // (`MergePhase::Committed`) apart from one that was interrupted and
+    // should simply be discarded and retried with a fresh output segment.
+    pub fn merge_job_status(&self, output: &SegmentId) -> io::Result<Option<MergeJob>> {
+        let path = merge_job_filepath(output);
+        match self.ro_directory().and_then(|d| d.open_read(&path)) {
+            Ok(source) => {
+                let content = String::from_utf8_lossy(source.as_slice());
+                Ok(Some(json::decode(&content).unwrap()))
+            }
+            Err(ref err) if err.kind() == IOErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+fn write_gen_pointer(directory: &mut DirectoryPtr, live_generation: u64, max_generation: u64) -> io::Result<()> {
+    let content = format!("{} {}", live_generation, max_generation);
+    directory.atomic_write(&GEN_POINTER_FILEPATH, content.as_bytes())
 }
 
 
@@ -195,18 +1025,28 @@ impl Index {
 /////////////////////////
 // Segment
 
+// Magic bytes fronting every `.info` file, so a file from some other format
+// entirely is rejected outright rather than mis-parsed.
+const SEGMENT_INFO_MAGIC: [u8; 4] = [0x74, 0x61, 0x6e, 0x74]; // "tant"
+
+// `.info` format version. Bump this if the header or body layout changes;
+// `read_info` rejects anything else instead of guessing.
+const SEGMENT_INFO_VERSION: u8 = 1;
+
 #[derive(Clone,Debug,RustcDecodable,RustcEncodable)]
 pub struct SegmentInfo {
 	pub max_doc: DocId,
 }
 
 
+#[derive(Clone)]
 pub enum SegmentComponent {
     INFO,
     POSTINGS,
     // POSITIONS,
     TERMS,
     STORE,
+    DELETE,
 }
 
 #[derive(Debug, Clone)]
@@ -228,6 +1068,7 @@ impl Segment {
             SegmentComponent::POSTINGS => ".idx",
             SegmentComponent::TERMS => ".term",
             SegmentComponent::STORE => ".store",
+            SegmentComponent::DELETE => ".del",
         }
     }
 
@@ -246,4 +1087,627 @@ impl Segment {
         let path = self.relative_path(&component);
         self.index.directory.write().unwrap().open_write(&path)
     }
+
+    // Writes `info` to the `.info` component behind a small fixed header --
+    // magic bytes, format version, doc count -- so a future format change or
+    // a truncated file is caught at open time instead of being silently
+    // mis-parsed by `read_info`.
+    pub fn write_info(&self, info: &SegmentInfo) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(9);
+        buf.extend_from_slice(&SEGMENT_INFO_MAGIC);
+        buf.push(SEGMENT_INFO_VERSION);
+        encode_u32_be(info.max_doc, &mut buf);
+        let mut writer = try!(self.open_write(SegmentComponent::INFO));
+        try!(writer.write_all(&buf));
+        writer.flush()
+    }
+
+    pub fn read_info(&self,) -> io::Result<SegmentInfo> {
+        let source = try!(self.open_read(SegmentComponent::INFO));
+        let bytes = source.as_slice();
+        if bytes.len() < 9 {
+            return Err(io::Error::new(IOErrorKind::InvalidData,
+                "truncated .info file: shorter than its header"));
+        }
+        if bytes[0..4] != SEGMENT_INFO_MAGIC {
+            return Err(io::Error::new(IOErrorKind::InvalidData,
+                ".info file is missing its magic bytes"));
+        }
+        if bytes[4] != SEGMENT_INFO_VERSION {
+            return Err(io::Error::new(IOErrorKind::InvalidData,
+                format!("unsupported .info format version {}", bytes[4])));
+        }
+        let max_doc = decode_u32_be(&bytes[5..9]);
+        Ok(SegmentInfo { max_doc: max_doc })
+    }
+
+    // Reads this segment's deletes, if any. A segment with no `.del` file
+    // yet has no deleted docs, sized to its actual doc count so a
+    // subsequent delete doesn't shrink `num_deleted`'s scan range.
+    pub fn open_delete_bitset(&self,) -> io::Result<DeleteBitSet> {
+        match self.open_read(SegmentComponent::DELETE) {
+            Ok(source) => Ok(DeleteBitSet::from_bytes(source.as_slice())),
+            Err(ref err) if err.kind() == IOErrorKind::NotFound => {
+                let info = try!(self.read_info());
+                Ok(DeleteBitSet::with_max_doc(info.max_doc))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn write_delete_bitset(&self, delete_bitset: &DeleteBitSet) -> io::Result<()> {
+        let mut writer = try!(self.open_write(SegmentComponent::DELETE));
+        try!(writer.write_all(&delete_bitset.to_bytes()));
+        writer.flush()
+    }
+
+    // Codec this segment's STORE component is written with, per the owning
+    // `Index`'s `IndexSettings` at creation time.
+    fn store_compression(&self,) -> StoreCompression {
+        self.index.metas.read().unwrap().store_compression
+    }
+
+    pub fn open_store_writer(&self,) -> io::Result<StoreWriter> {
+        let writer = try!(self.open_write(SegmentComponent::STORE));
+        StoreWriter::new(writer, self.store_compression())
+    }
+
+    pub fn open_store_reader(&self,) -> io::Result<StoreReader> {
+        let source = try!(self.open_read(SegmentComponent::STORE));
+        StoreReader::open(source)
+    }
+}
+
+// Magic bytes fronting every block-compressed `.store` file.
+const STORE_MAGIC: [u8; 4] = [0x74, 0x73, 0x74, 0x6f]; // "tsto"
+
+// `.store` format version.
+const STORE_FORMAT_VERSION: u8 = 1;
+
+// Documents per block. `StoreReader::read_doc` decompresses at most one
+// block, so this bounds how much unrelated data comes along for the ride
+// when reading a single document out of the store.
+const STORE_DOCS_PER_BLOCK: usize = 128;
+
+fn compress_block(codec: StoreCompression, data: &[u8]) -> io::Result<Vec<u8>> {
+    match codec {
+        StoreCompression::None => Ok(data.to_vec()),
+        StoreCompression::Zstd(level) => zstd::stream::encode_all(data, level),
+        StoreCompression::Lz4 => {
+            lz4::block::compress(data, None, false)
+                .map_err(|e| io::Error::new(IOErrorKind::Other, e))
+        }
+    }
+}
+
+fn decompress_block(codec: StoreCompression, data: &[u8], uncompressed_len: usize) -> io::Result<Vec<u8>> {
+    match codec {
+        StoreCompression::None => Ok(data.to_vec()),
+        StoreCompression::Zstd(_) => zstd::stream::decode_all(data),
+        StoreCompression::Lz4 => {
+            lz4::block::decompress(data, Some(uncompressed_len as i32))
+                .map_err(|e| io::Error::new(IOErrorKind::Other, e))
+        }
+    }
+}
+
+// Buffers whole documents and flushes a compressed block every
+// `STORE_DOCS_PER_BLOCK` docs, recording each block's starting offset so
+// `StoreReader::read_doc` can seek straight to the block holding the
+// document it wants instead of inflating the whole component.
+pub struct StoreWriter {
+    writer: WritePtr,
+    compression: StoreCompression,
+    pending_docs: Vec<Vec<u8>>,
+    block_offsets: Vec<u64>,
+    offset: u64,
+}
+
+impl StoreWriter {
+    fn new(mut writer: WritePtr, compression: StoreCompression) -> io::Result<StoreWriter> {
+        let mut header = Vec::with_capacity(6);
+        header.extend_from_slice(&STORE_MAGIC);
+        header.push(STORE_FORMAT_VERSION);
+        header.push(compression.codec_tag());
+        try!(writer.write_all(&header));
+        Ok(StoreWriter {
+            writer: writer,
+            compression: compression,
+            pending_docs: Vec::new(),
+            block_offsets: Vec::new(),
+            offset: header.len() as u64,
+        })
+    }
+
+    pub fn store_doc(&mut self, doc_bytes: &[u8]) -> io::Result<()> {
+        self.pending_docs.push(doc_bytes.to_vec());
+        if self.pending_docs.len() >= STORE_DOCS_PER_BLOCK {
+            try!(self.flush_block());
+        }
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> io::Result<()> {
+        if self.pending_docs.is_empty() {
+            return Ok(());
+        }
+        let mut uncompressed = Vec::new();
+        for doc_bytes in &self.pending_docs {
+            encode_u32_be(doc_bytes.len() as u32, &mut uncompressed);
+            uncompressed.extend_from_slice(doc_bytes);
+        }
+        let compressed = try!(compress_block(self.compression, &uncompressed));
+        // A block that doesn't actually shrink is stored raw instead: the
+        // per-block codec tag is what lets a store mix compressed and
+        // uncompressed blocks. A `.store` without the `STORE_MAGIC` header,
+        // such as one written before this block format existed, is rejected
+        // by `StoreReader::open`.
+        let (block_codec, payload) = if compressed.len() < uncompressed.len() {
+            (self.compression, compressed)
+        } else {
+            (StoreCompression::None, uncompressed.clone())
+        };
+
+        let mut block_header = Vec::with_capacity(9);
+        block_header.push(block_codec.codec_tag());
+        encode_u32_be(uncompressed.len() as u32, &mut block_header);
+        encode_u32_be(payload.len() as u32, &mut block_header);
+
+        self.block_offsets.push(self.offset);
+        try!(self.writer.write_all(&block_header));
+        try!(self.writer.write_all(&payload));
+        self.offset += (block_header.len() + payload.len()) as u64;
+        self.pending_docs.clear();
+        Ok(())
+    }
+
+    // Flushes the final partial block and writes the block-offset footer.
+    // Must be called once all documents have been stored.
+    pub fn close(mut self) -> io::Result<()> {
+        try!(self.flush_block());
+        let mut footer = Vec::with_capacity(self.block_offsets.len() * 8 + 4);
+        for block_offset in &self.block_offsets {
+            encode_u64_be(*block_offset, &mut footer);
+        }
+        encode_u32_be(self.block_offsets.len() as u32, &mut footer);
+        try!(self.writer.write_all(&footer));
+        self.writer.flush()
+    }
+}
+
+// Reads documents out of a block-compressed `.store`, decompressing only
+// the block containing the requested document.
+pub struct StoreReader {
+    source: ReadOnlySource,
+    block_offsets: Vec<u64>,
+}
+
+impl StoreReader {
+    fn open(source: ReadOnlySource) -> io::Result<StoreReader> {
+        let block_offsets = {
+            let bytes = source.as_slice();
+            if bytes.len() < 6 {
+                return Err(io::Error::new(IOErrorKind::InvalidData,
+                    "truncated .store file: shorter than its header"));
+            }
+            if bytes[0..4] != STORE_MAGIC {
+                return Err(io::Error::new(IOErrorKind::InvalidData,
+                    ".store file is missing its magic bytes"));
+            }
+            if bytes[4] != STORE_FORMAT_VERSION {
+                return Err(io::Error::new(IOErrorKind::InvalidData,
+                    format!("unsupported .store format version {}", bytes[4])));
+            }
+            // bytes[5] is the file's default codec tag, kept for forward
+            // compatibility; each block additionally carries its own tag,
+            // which is what `read_doc` actually decodes with.
+            if bytes.len() < 10 {
+                return Err(io::Error::new(IOErrorKind::InvalidData,
+                    "truncated .store file: missing block-offset footer"));
+            }
+            let num_blocks = decode_u32_be(&bytes[bytes.len() - 4..]) as usize;
+            // A `.store` truncated before `StoreWriter::close()` finished
+            // writing its footer has garbage trailing bytes, which would
+            // otherwise be read as `num_blocks` and underflow this
+            // subtraction (or build an out-of-bounds `table_start`).
+            let footer_bytes = match num_blocks.checked_mul(8).and_then(|n| n.checked_add(4)) {
+                Some(footer_bytes) if footer_bytes <= bytes.len() => footer_bytes,
+                _ => return Err(io::Error::new(IOErrorKind::InvalidData,
+                    "truncated or corrupt .store file: block-offset footer \
+                     doesn't fit in the file")),
+            };
+            let table_start = bytes.len() - footer_bytes;
+            let mut block_offsets = Vec::with_capacity(num_blocks);
+            for i in 0..num_blocks {
+                let start = table_start + i * 8;
+                block_offsets.push(decode_u64_be(&bytes[start..start + 8]));
+            }
+            block_offsets
+        };
+        Ok(StoreReader {
+            source: source,
+            block_offsets: block_offsets,
+        })
+    }
+
+    // `doc_index` is the document's 0-based position among the docs
+    // appended to this store (write order), not a global `DocId`.
+    pub fn read_doc(&self, doc_index: usize) -> io::Result<Vec<u8>> {
+        let block_index = doc_index / STORE_DOCS_PER_BLOCK;
+        let doc_in_block = doc_index % STORE_DOCS_PER_BLOCK;
+        let offset = *try!(self.block_offsets.get(block_index)
+            .ok_or_else(|| io::Error::new(IOErrorKind::NotFound, "doc index out of range"))) as usize;
+
+        let bytes = self.source.as_slice();
+        if offset + 9 > bytes.len() {
+            return Err(io::Error::new(IOErrorKind::InvalidData,
+                "corrupt .store file: block header runs past end of file"));
+        }
+        let block_codec = try!(StoreCompression::from_codec_tag(bytes[offset]));
+        let uncompressed_len = decode_u32_be(&bytes[offset + 1..offset + 5]) as usize;
+        let compressed_len = decode_u32_be(&bytes[offset + 5..offset + 9]) as usize;
+        let payload_start = offset + 9;
+        let payload_end = match payload_start.checked_add(compressed_len) {
+            Some(payload_end) if payload_end <= bytes.len() => payload_end,
+            _ => return Err(io::Error::new(IOErrorKind::InvalidData,
+                "corrupt .store file: block payload runs past end of file")),
+        };
+        let payload = &bytes[payload_start..payload_end];
+        let block = try!(decompress_block(block_codec, payload, uncompressed_len));
+
+        let corrupt_block = || io::Error::new(IOErrorKind::InvalidData,
+            "corrupt .store file: decompressed block is shorter than its doc table claims");
+
+        let mut pos = 0;
+        for _ in 0..doc_in_block {
+            if pos + 4 > block.len() {
+                return Err(corrupt_block());
+            }
+            let len = decode_u32_be(&block[pos..pos + 4]) as usize;
+            pos = try!(pos.checked_add(4 + len).ok_or_else(corrupt_block));
+        }
+        if pos + 4 > block.len() {
+            return Err(corrupt_block());
+        }
+        let len = decode_u32_be(&block[pos..pos + 4]) as usize;
+        pos += 4;
+        let end = try!(pos.checked_add(len).ok_or_else(corrupt_block));
+        if end > block.len() {
+            return Err(corrupt_block());
+        }
+        Ok(block[pos..end].to_vec())
+    }
+}
+
+// A packed bitset of deleted `DocId`s, one bit per doc, so `Searcher` can
+// skip a deleted doc without needing to touch POSTINGS/TERMS to do it.
+#[derive(Clone,Debug)]
+pub struct DeleteBitSet {
+    max_doc: DocId,
+    bits: Vec<u8>,
+}
+
+impl DeleteBitSet {
+    pub fn with_max_doc(max_doc: DocId) -> DeleteBitSet {
+        let num_bytes = (max_doc as usize + 7) / 8;
+        DeleteBitSet {
+            max_doc: max_doc,
+            bits: vec![0u8; num_bytes],
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> DeleteBitSet {
+        if bytes.len() < 4 {
+            return DeleteBitSet::with_max_doc(0);
+        }
+        let max_doc = decode_u32_be(&bytes[0..4]);
+        DeleteBitSet {
+            max_doc: max_doc,
+            bits: bytes[4..].to_vec(),
+        }
+    }
+
+    fn to_bytes(&self,) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + self.bits.len());
+        encode_u32_be(self.max_doc, &mut buf);
+        buf.extend_from_slice(&self.bits);
+        buf
+    }
+
+    pub fn is_deleted(&self, doc: DocId) -> bool {
+        let byte = (doc / 8) as usize;
+        let bit = doc % 8;
+        match self.bits.get(byte) {
+            Some(b) => (b >> bit) & 1 == 1,
+            None => false,
+        }
+    }
+
+    pub fn delete(&mut self, doc: DocId) {
+        let byte = (doc / 8) as usize;
+        let bit = doc % 8;
+        if byte >= self.bits.len() {
+            self.bits.resize(byte + 1, 0);
+        }
+        self.bits[byte] |= 1 << bit;
+    }
+
+    pub fn num_deleted(&self,) -> DocId {
+        let mut count = 0;
+        for doc in 0..self.max_doc {
+            if self.is_deleted(doc) {
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+// Self-rolled FNV-1a 32-bit hash over a segment's POSTINGS/TERMS/STORE
+// bytes; a missing component contributes nothing, same as an empty one.
+// Stored in `SegmentMeta::checksum` by `publish_segment`.
+fn segment_checksum(segment: &Segment) -> io::Result<u32> {
+    const FNV_OFFSET_BASIS: u32 = 0x811c9dc5;
+    const FNV_PRIME: u32 = 0x01000193;
+    let mut hash = FNV_OFFSET_BASIS;
+    for component in [SegmentComponent::POSTINGS, SegmentComponent::TERMS, SegmentComponent::STORE].iter() {
+        match segment.open_read(component.clone()) {
+            Ok(source) => {
+                for &byte in source.as_slice() {
+                    hash ^= byte as u32;
+                    hash = hash.wrapping_mul(FNV_PRIME);
+                }
+            }
+            Err(ref err) if err.kind() == IOErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::schema::Schema;
+
+    fn segment_meta(segment_id: SegmentId) -> SegmentMeta {
+        SegmentMeta {
+            segment_id: segment_id,
+            max_doc: 0,
+            num_deleted_docs: 0,
+            checksum: 0,
+        }
+    }
+
+    #[test]
+    fn test_load_metas_skips_records_already_in_generation_snapshot() {
+        let mut index = Index::create_in_ram(Schema::new());
+        for _ in 0..3 {
+            index.append_log_record(MetaLogRecord::AddSegment(segment_meta(SegmentId::new()))).unwrap();
+        }
+        index.commit_generation_internal().unwrap();
+        let before = index.metas.read().unwrap().segments.len();
+
+        // Simulate the crash window between commit_generation_internal
+        // writing the generation file and truncating meta.log: the log
+        // still holds a record (lsn 1) that the snapshot we just wrote
+        // already folded in.
+        let stale_frame = {
+            let payload = json::encode(&MetaLogRecord::AddSegment(segment_meta(SegmentId::new()))).unwrap();
+            let mut frame = Vec::new();
+            encode_u64_be(1, &mut frame);
+            encode_u32_be(payload.len() as u32, &mut frame);
+            frame.extend_from_slice(payload.as_bytes());
+            frame
+        };
+        {
+            let mut directory = index.rw_directory().unwrap();
+            directory.atomic_write(&LOG_FILEPATH, &stale_frame).unwrap();
+        }
+        index.log_state.write().unwrap().writer = None;
+
+        index.load_metas().unwrap();
+        assert_eq!(index.metas.read().unwrap().segments.len(), before);
+    }
+
+    #[test]
+    fn test_load_metas_heals_torn_log_tail() {
+        let mut index = Index::create_in_ram(Schema::new());
+        index.append_log_record(MetaLogRecord::AddSegment(segment_meta(SegmentId::new()))).unwrap();
+
+        // A length header promising more payload bytes than were actually
+        // written: an append interrupted mid-write.
+        {
+            let mut bytes = index.read_log_bytes().unwrap();
+            encode_u64_be(2, &mut bytes);
+            encode_u32_be(9999, &mut bytes);
+            bytes.extend_from_slice(b"short");
+            let mut directory = index.rw_directory().unwrap();
+            directory.atomic_write(&LOG_FILEPATH, &bytes).unwrap();
+        }
+        index.log_state.write().unwrap().writer = None;
+
+        index.load_metas().unwrap();
+        assert_eq!(index.metas.read().unwrap().segments.len(), 1);
+
+        // The healed log must no longer contain the torn tail, or this
+        // next append would be mistaken for a continuation of it.
+        index.append_log_record(MetaLogRecord::AddSegment(segment_meta(SegmentId::new()))).unwrap();
+        index.load_metas().unwrap();
+        assert_eq!(index.metas.read().unwrap().segments.len(), 2);
+    }
+
+    #[test]
+    fn test_rollback_restores_old_segment_list() {
+        let mut index = Index::create_in_ram(Schema::new());
+        let seg_a = segment_meta(SegmentId::new());
+        index.append_log_record(MetaLogRecord::AddSegment(seg_a.clone())).unwrap();
+        let old_generation = index.commit_generation_internal().unwrap();
+
+        index.append_log_record(MetaLogRecord::AddSegment(segment_meta(SegmentId::new()))).unwrap();
+        index.commit_generation_internal().unwrap();
+        assert_eq!(index.metas.read().unwrap().segments.len(), 2);
+
+        index.rollback(old_generation).unwrap();
+        assert_eq!(index.segment_ids(), vec![seg_a.segment_id]);
+    }
+
+    #[test]
+    fn test_rollback_to_generation_zero_on_never_committed_index() {
+        let mut index = Index::create_in_ram(Schema::new());
+        index.append_log_record(MetaLogRecord::AddSegment(segment_meta(SegmentId::new()))).unwrap();
+        index.commit_generation_internal().unwrap();
+        assert_eq!(index.metas.read().unwrap().segments.len(), 1);
+
+        index.rollback(0).unwrap();
+        assert_eq!(index.segment_ids(), Vec::new());
+        assert_eq!(index.commit_generation(), 0);
+    }
+
+    #[test]
+    fn test_delete_bitset_round_trip() {
+        let mut bitset = DeleteBitSet::with_max_doc(20);
+        bitset.delete(3);
+        bitset.delete(17);
+
+        let bytes = bitset.to_bytes();
+        let reloaded = DeleteBitSet::from_bytes(&bytes);
+
+        for doc in 0..20 {
+            assert_eq!(reloaded.is_deleted(doc), doc == 3 || doc == 17);
+        }
+        assert_eq!(reloaded.num_deleted(), 2);
+    }
+
+    #[test]
+    fn test_delete_bitset_written_through_segment_round_trips() {
+        let index = Index::create_in_ram(Schema::new());
+        let segment = index.new_segment();
+        let mut bitset = DeleteBitSet::with_max_doc(4);
+        bitset.delete(2);
+        segment.write_delete_bitset(&bitset).unwrap();
+
+        let reloaded = segment.open_delete_bitset().unwrap();
+        assert!(reloaded.is_deleted(2));
+        assert!(!reloaded.is_deleted(1));
+    }
+
+    #[test]
+    fn test_delete_docs_updates_num_deleted_docs_and_survives_reload() {
+        let mut index = Index::create_in_ram(Schema::new());
+        let segment = index.new_segment();
+        segment.write_info(&SegmentInfo { max_doc: 4 }).unwrap();
+        let segment_id = segment.segment_id.clone();
+
+        let mut segment_meta = segment_meta(segment_id.clone());
+        segment_meta.max_doc = 4;
+        index.append_log_record(MetaLogRecord::AddSegment(segment_meta)).unwrap();
+        index.commit_generation_internal().unwrap();
+
+        index.delete_docs(&segment_id, &[1, 2]).unwrap();
+        assert_eq!(index.metas.read().unwrap().segments[0].num_deleted_docs, 2);
+
+        index.log_state.write().unwrap().writer = None;
+        index.load_metas().unwrap();
+        assert_eq!(index.metas.read().unwrap().segments[0].num_deleted_docs, 2);
+    }
+
+    fn store_round_trip_for(compression: StoreCompression) {
+        let settings = IndexSettings { store_compression: compression };
+        let index = Index::create_in_ram_with_settings(Schema::new(), settings);
+        let segment = index.new_segment();
+
+        // More than one `STORE_DOCS_PER_BLOCK` worth of docs, so this
+        // exercises seeking to a block other than the first.
+        let num_docs = STORE_DOCS_PER_BLOCK * 2 + 5;
+        {
+            let mut writer = segment.open_store_writer().unwrap();
+            for doc_index in 0..num_docs {
+                writer.store_doc(format!("doc-{}", doc_index).as_bytes()).unwrap();
+            }
+            writer.close().unwrap();
+        }
+
+        let reader = segment.open_store_reader().unwrap();
+        for doc_index in 0..num_docs {
+            let doc_bytes = reader.read_doc(doc_index).unwrap();
+            assert_eq!(doc_bytes, format!("doc-{}", doc_index).into_bytes());
+        }
+    }
+
+    #[test]
+    fn test_store_round_trip_uncompressed() {
+        store_round_trip_for(StoreCompression::None);
+    }
+
+    #[test]
+    fn test_store_round_trip_zstd() {
+        store_round_trip_for(StoreCompression::Zstd(3));
+    }
+
+    #[test]
+    fn test_store_round_trip_lz4() {
+        store_round_trip_for(StoreCompression::Lz4);
+    }
+
+    #[test]
+    fn test_store_reader_rejects_truncated_footer() {
+        let index = Index::create_in_ram(Schema::new());
+        let segment = index.new_segment();
+        {
+            let mut writer = segment.open_store_writer().unwrap();
+            writer.store_doc(b"only doc").unwrap();
+            writer.close().unwrap();
+        }
+
+        // Truncate to simulate a crash before StoreWriter::close()
+        // finished writing the block-offset footer: the trailing bytes
+        // left behind are garbage, not a real num_blocks count.
+        let truncated = {
+            let source = segment.open_read(SegmentComponent::STORE).unwrap();
+            let bytes = source.as_slice();
+            bytes[..bytes.len() - 2].to_vec()
+        };
+        {
+            let mut writer = segment.open_write(SegmentComponent::STORE).unwrap();
+            writer.write_all(&truncated).unwrap();
+            writer.flush().unwrap();
+        }
+
+        assert!(segment.open_store_reader().is_err());
+    }
+
+    #[test]
+    fn test_store_reader_rejects_corrupt_compressed_length() {
+        let index = Index::create_in_ram(Schema::new());
+        let segment = index.new_segment();
+        {
+            let mut writer = segment.open_store_writer().unwrap();
+            writer.store_doc(b"only doc").unwrap();
+            writer.close().unwrap();
+        }
+
+        // Blow up the first block's recorded compressed length so its
+        // payload would run past the end of the file. open()'s footer/magic
+        // checks can't catch this -- only read_doc()'s bounds check on the
+        // payload slice can.
+        let corrupted = {
+            let source = segment.open_read(SegmentComponent::STORE).unwrap();
+            let mut bytes = source.as_slice().to_vec();
+            // The file header is 6 bytes (magic + version + codec tag), so
+            // the first block's header starts at offset 6; its
+            // compressed_len field is the 4 bytes after the block's own
+            // codec tag and uncompressed_len.
+            bytes[11..15].copy_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+            bytes
+        };
+        {
+            let mut writer = segment.open_write(SegmentComponent::STORE).unwrap();
+            writer.write_all(&corrupted).unwrap();
+            writer.flush().unwrap();
+        }
+
+        let reader = segment.open_store_reader().unwrap();
+        assert!(reader.read_doc(0).is_err());
+    }
 }
\ No newline at end of file